@@ -0,0 +1,189 @@
+//! Parsing and terminal rendering of `text/gemini` documents.
+
+/// A single parsed line of a `text/gemini` document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    Heading { level: u8, text: String },
+    Link { url: String, label: Option<String> },
+    ListItem(String),
+    Quote(String),
+    Preformatted {
+        alt: Option<String>,
+        lines: Vec<String>,
+    },
+    Text(String),
+}
+
+/// Tokenizes a `text/gemini` body into its line types. Lines inside a
+/// ` ``` ` preformatted fence are collected verbatim and never parsed as
+/// anything else.
+pub fn parse(body: &str) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut in_preformatted = false;
+    let mut preformatted_alt = None;
+    let mut preformatted_lines = Vec::new();
+
+    for raw_line in body.lines() {
+        if let Some(alt) = raw_line.strip_prefix("```") {
+            if in_preformatted {
+                lines.push(Line::Preformatted {
+                    alt: preformatted_alt.take(),
+                    lines: std::mem::take(&mut preformatted_lines),
+                });
+                in_preformatted = false;
+            } else {
+                in_preformatted = true;
+                preformatted_alt = (!alt.is_empty()).then(|| alt.to_owned());
+            }
+            continue;
+        }
+
+        if in_preformatted {
+            preformatted_lines.push(raw_line.to_owned());
+            continue;
+        }
+
+        if let Some(rest) = raw_line.strip_prefix("=>") {
+            let rest = rest.trim_start();
+            let mut parts = rest.splitn(2, char::is_whitespace);
+            let url = parts.next().unwrap_or("").to_owned();
+            let label = parts
+                .next()
+                .map(|label| label.trim_start().to_owned())
+                .filter(|label| !label.is_empty());
+            lines.push(Line::Link { url, label });
+        } else if let Some(rest) = raw_line.strip_prefix("###") {
+            lines.push(Line::Heading {
+                level: 3,
+                text: rest.trim_start().to_owned(),
+            });
+        } else if let Some(rest) = raw_line.strip_prefix("##") {
+            lines.push(Line::Heading {
+                level: 2,
+                text: rest.trim_start().to_owned(),
+            });
+        } else if let Some(rest) = raw_line.strip_prefix('#') {
+            lines.push(Line::Heading {
+                level: 1,
+                text: rest.trim_start().to_owned(),
+            });
+        } else if let Some(rest) = raw_line.strip_prefix("* ") {
+            lines.push(Line::ListItem(rest.to_owned()));
+        } else if let Some(rest) = raw_line.strip_prefix('>') {
+            lines.push(Line::Quote(rest.trim_start().to_owned()));
+        } else {
+            lines.push(Line::Text(raw_line.to_owned()));
+        }
+    }
+
+    // An unterminated fence still renders whatever it collected.
+    if in_preformatted {
+        lines.push(Line::Preformatted {
+            alt: preformatted_alt,
+            lines: preformatted_lines,
+        });
+    }
+
+    lines
+}
+
+/// Renders `lines` to the terminal, numbering link lines in the order they
+/// appear. Returns the links in that order so a caller can resolve a typed
+/// number to a URL.
+pub fn render(lines: &[Line]) -> Vec<String> {
+    let mut links = Vec::new();
+    for line in lines {
+        match line {
+            Line::Heading { level, text } => {
+                println!("\x1b[1m{} {text}\x1b[0m", "#".repeat(*level as usize));
+            }
+            Line::Link { url, label } => {
+                links.push(url.clone());
+                let n = links.len();
+                match label {
+                    Some(label) => println!("[{n}] {label} ({url})"),
+                    None => println!("[{n}] {url}"),
+                }
+            }
+            Line::ListItem(text) => println!("  • {text}"),
+            Line::Quote(text) => println!("  │ {text}"),
+            Line::Preformatted { alt, lines } => {
+                if let Some(alt) = alt {
+                    println!("--- {alt} ---");
+                }
+                for line in lines {
+                    println!("{line}");
+                }
+            }
+            Line::Text(text) => println!("{text}"),
+        }
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_headings_by_level() {
+        let lines = parse("# one\n## two\n### three");
+        assert_eq!(
+            lines,
+            vec![
+                Line::Heading { level: 1, text: "one".to_owned() },
+                Line::Heading { level: 2, text: "two".to_owned() },
+                Line::Heading { level: 3, text: "three".to_owned() },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_link_with_and_without_label() {
+        let lines = parse("=> gemini://example.com/ Example\n=> gemini://example.com/bare");
+        assert_eq!(
+            lines,
+            vec![
+                Line::Link {
+                    url: "gemini://example.com/".to_owned(),
+                    label: Some("Example".to_owned()),
+                },
+                Line::Link {
+                    url: "gemini://example.com/bare".to_owned(),
+                    label: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn preformatted_fence_collects_lines_verbatim_and_keeps_alt() {
+        let lines = parse("```rust\nfn main() {}\n# not a heading\n```");
+        assert_eq!(
+            lines,
+            vec![Line::Preformatted {
+                alt: Some("rust".to_owned()),
+                lines: vec!["fn main() {}".to_owned(), "# not a heading".to_owned()],
+            }]
+        );
+    }
+
+    #[test]
+    fn unterminated_fence_still_renders_what_it_collected() {
+        let lines = parse("```\nunterminated");
+        assert_eq!(
+            lines,
+            vec![Line::Preformatted {
+                alt: None,
+                lines: vec!["unterminated".to_owned()],
+            }]
+        );
+    }
+
+    #[test]
+    fn render_numbers_links_in_order_and_returns_them() {
+        let lines = parse("=> gemini://a/ A\ntext\n=> gemini://b/ B");
+        let links = render(&lines);
+        assert_eq!(links, vec!["gemini://a/".to_owned(), "gemini://b/".to_owned()]);
+    }
+}
@@ -1,16 +1,34 @@
 use clap::Parser;
-use native_tls::{TlsConnector, TlsStream};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS, NON_ALPHANUMERIC};
+use std::collections::HashSet;
+use std::fs;
 use std::io::{self, BufRead, BufReader, Read, Write};
-use std::net::TcpStream;
+use std::path::PathBuf;
+use url::Url;
+
+mod gemtext;
+mod tls;
+
+use tls::GeminiStream;
 
 const GEMINI_PORT: u16 = 1965;
 const DEFAULT_HOST: &'static str = "gemini.circumlunar.space";
+const DEFAULT_MAX_REDIRECTS: u32 = 5;
+
+/// Characters the Gemini spec requires percent-encoded in a request URL,
+/// beyond what's already disallowed in a raw path: controls plus space,
+/// the same set agate and other reference servers encode on the way in.
+const GEMINI_SPECIALS: &AsciiSet = &CONTROLS.add(b' ');
+
+/// A Gemini request line is the URL followed by CRLF, capped at 1024 bytes
+/// including the CRLF.
+const MAX_REQUEST_LINE: usize = 1024;
 
 #[derive(Debug, Clone)]
 struct Response {
     status: StatusCodes,
     meta: String,
-    body: String,
+    body: Vec<u8>,
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
@@ -81,31 +99,93 @@ struct Cli {
     domain: String,
     #[arg(default_value_t = GEMINI_PORT, value_parser = clap::value_parser!(u16).range(1..), help = "the port the gemini server runs on")]
     port: u16,
+    #[arg(long, default_value_t = DEFAULT_MAX_REDIRECTS, help = "maximum number of redirects to follow before giving up")]
+    max_redirects: u32,
+    #[arg(short, long, help = "where to save a non-text response (defaults to a name derived from the URL)")]
+    output: Option<String>,
+}
+
+/// Where a link, or a 30/31 response's `meta`, points, resolved against
+/// the page that produced it.
+enum Reference {
+    SameHost(Url),
+    CrossHost(Url),
+    UnsupportedScheme(Url),
+    /// `target` couldn't be parsed at all, even relative to `current` — a
+    /// malformed host or a broken IPv6 literal, for example. Carries the raw
+    /// string as sent by the server/page, not `current`, so callers can
+    /// report what was actually rejected.
+    Unparseable(String),
+}
+
+/// The port a connection to `url` should actually use. `gemini` isn't a
+/// WHATWG "special scheme", so `Url` has no registered default port for it:
+/// `port()` is `None` when no port is given and `Some(1965)` for an explicit
+/// ":1965", although both connect to the same port.
+fn effective_port(url: &Url) -> u16 {
+    url.port().unwrap_or(GEMINI_PORT)
+}
+
+fn resolve_reference(current: &Url, target: &str) -> Reference {
+    let Ok(target) = current.join(target) else {
+        return Reference::Unparseable(target.to_owned());
+    };
+    if target.scheme() != "gemini" {
+        return Reference::UnsupportedScheme(target);
+    }
+    if target.host_str() == current.host_str() && effective_port(&target) == effective_port(current)
+    {
+        Reference::SameHost(target)
+    } else {
+        Reference::CrossHost(target)
+    }
 }
 
-fn create_stream(domain: &str) -> TlsStream<TcpStream> {
-    let connector = TlsConnector::builder()
-        .danger_accept_invalid_certs(true)
-        .build()
-        .unwrap();
-    let stream = TcpStream::connect((domain, GEMINI_PORT)).unwrap();
-    connector.connect(domain, stream).unwrap()
+fn confirm(prompt: &str) -> bool {
+    print!("{prompt} [y/N] ");
+    io::stdout().flush().ok();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).expect("failed to read answer");
+    answer.trim().eq_ignore_ascii_case("y")
 }
 
-fn build_uri(domain: &str, resource: &str) -> String {
-    let scheme = if domain.starts_with("gemini://") {
-        ""
+/// Builds the URL for the first request of the session out of the raw
+/// `domain` and `port` command-line arguments and the resource typed at the
+/// start-up prompt; `domain` (unlike a link target) may be missing a scheme
+/// entirely or carry its own embedded port.
+fn initial_url(domain: &str, port: u16, resource: &str) -> Result<Url, String> {
+    let base = if domain.contains("://") {
+        domain.to_owned()
     } else {
-        "gemini://"
+        format!("gemini://{domain}")
     };
-    format!("{}{}{}\r\n", scheme, domain, resource)
+    let mut url = Url::parse(&base).map_err(|err| format!("invalid domain {domain:?}: {err}"))?;
+    if url.scheme() != "gemini" {
+        return Err(format!("unsupported scheme: {}", url.scheme()));
+    }
+    if url.port().is_none() {
+        url.set_port(Some(port))
+            .map_err(|()| "failed to set port".to_owned())?;
+    }
+    let encoded = utf8_percent_encode(resource, GEMINI_SPECIALS).to_string();
+    let url = url
+        .join(&encoded)
+        .map_err(|err| format!("invalid resource {resource:?}: {err}"))?;
+    // `resource` came straight from an interactive prompt, so it may itself
+    // be an absolute URL with its own scheme — `join` happily replaces the
+    // whole URL in that case, so the gemini-only check has to run again
+    // here, not just on the pre-join base.
+    if url.scheme() != "gemini" {
+        return Err(format!("unsupported scheme: {}", url.scheme()));
+    }
+    Ok(url)
 }
 
-fn send_request(stream: &mut TlsStream<TcpStream>, uri: &str) {
-    stream.write_all(uri.as_bytes()).expect("write failed");
+fn send_request(stream: &mut GeminiStream, request_line: &str) {
+    stream.write_all(request_line.as_bytes()).expect("write failed");
 }
 
-fn read_response_header(stream: &mut TlsStream<TcpStream>) -> Response {
+fn read_response_header(stream: &mut GeminiStream) -> Response {
     let mut space = String::new();
     let mut status_buf = String::new();
     stream.take(2).read_to_string(&mut status_buf).unwrap();
@@ -115,33 +195,75 @@ fn read_response_header(stream: &mut TlsStream<TcpStream>) -> Response {
     Response {
         status: status_buf.parse::<u8>().unwrap().into(),
         meta: buf.lines().next().unwrap().unwrap(),
-        body: String::from(""),
+        body: Vec::new(),
+    }
+}
+
+fn read_response_body(stream: &mut GeminiStream, response: &mut Response) {
+    stream.read_to_end(&mut response.body).unwrap();
+}
+
+/// Picks a path to save a non-text response to when the user gave no
+/// `--output`: the last path segment of the request URL (or "download" if
+/// it is empty), with an extension inferred from `mime` unless it already
+/// ends in one of `mime`'s extensions.
+fn default_filename(url: &Url, mime: &str) -> PathBuf {
+    let stem = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|segment| !segment.is_empty())
+        .unwrap_or("download");
+    match mime_guess::get_mime_extensions_str(mime) {
+        Some(exts) if !exts.iter().any(|ext| stem.ends_with(&format!(".{ext}"))) => {
+            PathBuf::from(format!("{stem}.{}", exts[0]))
+        }
+        _ => PathBuf::from(stem),
     }
 }
 
-fn read_response_body(stream: &mut TlsStream<TcpStream>, response: &mut Response) {
-    stream.read_to_string(&mut response.body).unwrap();
+fn save_to_file(body: &[u8], mime: &str, url: &Url, output: Option<&str>) {
+    let path = output
+        .map(PathBuf::from)
+        .unwrap_or_else(|| default_filename(url, mime));
+    fs::write(&path, body).expect("failed to write output file");
+    println!("Saved {} bytes to {}", body.len(), path.display());
 }
 
-fn handle_success(response: &mut Response, stream: &mut TlsStream<TcpStream>) {
-    assert!(
-        response.meta.starts_with("text/"),
-        "I only know how to handle text MIME types"
-    );
+fn handle_success(
+    response: &mut Response,
+    stream: &mut GeminiStream,
+    url: &Url,
+    output: Option<&str>,
+) -> Vec<String> {
     read_response_body(stream, response);
-    println!("Server returned:\n{}", response.body);
+    let mime = response.meta.split(';').next().unwrap_or(&response.meta).trim();
+    if mime == "text/gemini" {
+        let text = String::from_utf8_lossy(&response.body);
+        let lines = gemtext::parse(&text);
+        gemtext::render(&lines)
+    } else if mime.starts_with("text/") {
+        println!("Server returned:\n{}", String::from_utf8_lossy(&response.body));
+        Vec::new()
+    } else {
+        save_to_file(&response.body, mime, url, output);
+        Vec::new()
+    }
 }
 
-fn handle_response_header(mut response: Response, mut stream: TlsStream<TcpStream>) {
+/// Renders a final (non-redirect, non-input) response and returns the
+/// links found on the page, in display order, for navigation.
+fn handle_response_header(
+    mut response: Response,
+    mut stream: GeminiStream,
+    url: &Url,
+    output: Option<&str>,
+) -> Vec<String> {
     match response.status {
-        StatusCodes::Success => handle_success(&mut response, &mut stream),
+        StatusCodes::Success => return handle_success(&mut response, &mut stream, url, output),
         StatusCodes::NotFound => eprintln!("Page not found!"),
         StatusCodes::BadRequest => {
             eprintln!("Oops! Looks like we made a bad request :( please try again.")
         }
-        StatusCodes::RedirectPermanent | StatusCodes::RedirectTemporary => {
-            eprintln!("TODO: Got a redirect to {}", response.meta)
-        }
         StatusCodes::TemporaryFailure => eprint!(
             "We failed - but only for now. This is what the server returned: {}",
             response.meta
@@ -152,32 +274,342 @@ fn handle_response_header(mut response: Response, mut stream: TlsStream<TcpStrea
         ),
         _ => eprintln!("I don't know how to handle {:?}", response.status),
     }
+    Vec::new()
 }
 
-fn fetch_resource(domain: &str, resource: &str) -> TlsStream<TcpStream> {
-    let uri = build_uri(&domain, &resource);
-    eprintln!("INFO: Requesting {uri}");
-    let mut stream = create_stream(&domain);
-    send_request(&mut stream, &uri);
-    stream
+fn fetch_resource(url: &Url) -> Result<GeminiStream, String> {
+    let request_line = format!("{url}\r\n");
+    if request_line.len() > MAX_REQUEST_LINE {
+        return Err(format!(
+            "request line is {} bytes, over the {MAX_REQUEST_LINE}-byte limit",
+            request_line.len()
+        ));
+    }
+    eprintln!("INFO: Requesting {url}");
+    let host = url.host_str().expect("gemini URLs always have a host");
+    let mut stream = tls::create_stream(host, effective_port(url))?;
+    send_request(&mut stream, &request_line);
+    Ok(stream)
+}
+
+/// Fetches `url`, transparently following up to `max_redirects` 30/31
+/// responses. Cross-host redirects require user confirmation and redirects
+/// to a non-gemini scheme are refused; a redirect loop, too many redirects, or a redirect this
+/// client can't or won't follow fails the whole fetch rather than the
+/// process, so a bad link only aborts the one navigation that hit it.
+fn fetch_with_redirects(url: &Url, max_redirects: u32) -> Result<(Response, Url, GeminiStream), String> {
+    let mut url = url.clone();
+    let mut visited = HashSet::new();
+    let mut redirects = 0u32;
+
+    loop {
+        if !visited.insert(url.clone()) {
+            return Err(format!("redirect loop detected at {url}"));
+        }
+
+        let mut stream = fetch_resource(&url)?;
+        let response = read_response_header(&mut stream);
+
+        if response.status != StatusCodes::RedirectTemporary
+            && response.status != StatusCodes::RedirectPermanent
+        {
+            return Ok((response, url, stream));
+        }
+
+        redirects += 1;
+        if redirects > max_redirects {
+            return Err(format!("exceeded the maximum of {max_redirects} redirects"));
+        }
+
+        match resolve_reference(&url, &response.meta) {
+            Reference::SameHost(target) => {
+                eprintln!("INFO: Redirecting to {target}");
+                url = target;
+            }
+            Reference::CrossHost(target) => {
+                let prompt = format!("Redirected to a different host: {target}\nFollow it?");
+                if !confirm(&prompt) {
+                    return Err("redirect declined".to_owned());
+                }
+                url = target;
+            }
+            Reference::UnsupportedScheme(target) => {
+                // Unlike a cross-host redirect, there's nothing to confirm
+                // here: this client only speaks gemini, so it has no way to
+                // follow the redirect even if the user says yes.
+                return Err(format!("refusing to follow redirect to unsupported scheme: {target}"));
+            }
+            Reference::Unparseable(raw) => {
+                return Err(format!("refusing to follow redirect to an unparseable URL: {raw}"));
+            }
+        }
+    }
+}
+
+/// Fetches `url`, answering any status 10/11 prompts the server asks for
+/// along the way, and renders the final response. Status 11
+/// (`SensitiveInput`) reads answers without echoing to the terminal.
+/// Returns the URL actually displayed (which may differ from `url` if a
+/// prompt appended a query) and the page's links, or an error describing
+/// why the fetch couldn't complete.
+fn visit(url: &Url, max_redirects: u32, output: Option<&str>) -> Result<(Url, Vec<String>), String> {
+    let mut url = url.clone();
+    loop {
+        let (response, displayed, stream) = fetch_with_redirects(&url, max_redirects)?;
+        url = displayed;
+
+        if response.status != StatusCodes::Input && response.status != StatusCodes::SensitiveInput
+        {
+            let links = handle_response_header(response, stream, &url, output);
+            return Ok((url, links));
+        }
+
+        println!("{}", response.meta);
+        let answer = if response.status == StatusCodes::SensitiveInput {
+            rpassword::read_password().expect("failed to read input")
+        } else {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line).expect("failed to read input");
+            line.trim_end_matches(['\r', '\n']).to_owned()
+        };
+        let encoded = utf8_percent_encode(&answer, NON_ALPHANUMERIC).to_string();
+        url.set_query(Some(&encoded));
+    }
+}
+
+/// Resolves `target` against the current location. Returns the new `Url`
+/// to visit, or `None` (after printing why) if it points somewhere this
+/// client can't follow.
+fn navigate(current: &Url, target: &str) -> Option<Url> {
+    match resolve_reference(current, target) {
+        Reference::SameHost(url) | Reference::CrossHost(url) => Some(url),
+        Reference::UnsupportedScheme(url) => {
+            eprintln!("Cannot open non-gemini URL: {url}");
+            None
+        }
+        Reference::Unparseable(raw) => {
+            eprintln!("Cannot open malformed URL: {raw}");
+            None
+        }
+    }
+}
+
+fn print_prompt(url: &Url) {
+    print!("\n{url}> ");
+    io::stdout().flush().ok();
+}
+
+/// Visits `url` and reports a failed fetch to the user instead of
+/// propagating it, so a bad navigation fails just that one request and
+/// leaves the REPL in `main` sitting at the prompt.
+fn revisit(url: &Url, max_redirects: u32, output: Option<&str>) -> Option<(Url, Vec<String>)> {
+    match visit(url, max_redirects, output) {
+        Ok(result) => Some(result),
+        Err(err) => {
+            eprintln!("{err}");
+            None
+        }
+    }
 }
 
 fn main() -> io::Result<()> {
     let cli = Cli::parse();
-    let domain = cli.domain;
 
-    println!("What resource do you want to access on {domain}?: ");
+    println!("What resource do you want to access on {}?: ", cli.domain);
     let resource = if let Some(resource_str) = io::stdin().lines().next() {
         resource_str?
     } else {
         "/".to_owned()
     };
 
-    let mut stream = fetch_resource(&domain, &resource);
+    let mut url = match initial_url(&cli.domain, cli.port, &resource) {
+        Ok(url) => url,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
 
-    let response = read_response_header(&mut stream);
+    let mut history: Vec<Url> = Vec::new();
+    let mut links = Vec::new();
+    if let Some((displayed, new_links)) = revisit(&url, cli.max_redirects, cli.output.as_deref()) {
+        url = displayed;
+        links = new_links;
+    }
 
-    handle_response_header(response, stream);
+    loop {
+        print_prompt(&url);
+        let mut command = String::new();
+        if io::stdin().read_line(&mut command)? == 0 {
+            break;
+        }
+        let command = command.trim();
+
+        let target = if command.is_empty() {
+            continue;
+        } else if command.eq_ignore_ascii_case("q") {
+            break;
+        } else if command.eq_ignore_ascii_case("r") {
+            if let Some((displayed, new_links)) = revisit(&url, cli.max_redirects, cli.output.as_deref()) {
+                url = displayed;
+                links = new_links;
+            }
+            continue;
+        } else if command.eq_ignore_ascii_case("b") {
+            match history.pop() {
+                Some(prev_url) => url = prev_url,
+                None => {
+                    eprintln!("No previous page.");
+                    continue;
+                }
+            }
+            if let Some((displayed, new_links)) = revisit(&url, cli.max_redirects, cli.output.as_deref()) {
+                url = displayed;
+                links = new_links;
+            }
+            continue;
+        } else if command.starts_with("gemini://") {
+            command.to_owned()
+        } else if let Ok(n) = command.parse::<usize>() {
+            match n.checked_sub(1).and_then(|i| links.get(i)) {
+                Some(link) => link.clone(),
+                None => {
+                    eprintln!("No link numbered {n}.");
+                    continue;
+                }
+            }
+        } else {
+            eprintln!("Unknown command: {command}");
+            continue;
+        };
+
+        match navigate(&url, &target) {
+            Some(new_url) => {
+                history.push(url.clone());
+                url = new_url;
+                if let Some((displayed, new_links)) =
+                    revisit(&url, cli.max_redirects, cli.output.as_deref())
+                {
+                    url = displayed;
+                    links = new_links;
+                }
+            }
+            None => continue,
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initial_url_rejects_non_gemini_scheme_in_domain() {
+        assert!(initial_url("https://example.com", GEMINI_PORT, "/").is_err());
+    }
+
+    #[test]
+    fn initial_url_rejects_non_gemini_scheme_in_resource() {
+        // `resource` is attacker/typo-adjacent: it comes straight from an
+        // interactive prompt, and `Url::join` replaces scheme-and-all when
+        // it looks like an absolute URL.
+        let err = initial_url("example.com", GEMINI_PORT, "http://evil.com/payload")
+            .expect_err("absolute http resource must not be accepted");
+        assert!(err.contains("unsupported scheme"));
+    }
+
+    #[test]
+    fn initial_url_prefers_embedded_port_over_flag() {
+        let url = initial_url("example.com:1966", GEMINI_PORT, "/").unwrap();
+        assert_eq!(url.port(), Some(1966));
+    }
+
+    #[test]
+    fn initial_url_falls_back_to_flag_port() {
+        let url = initial_url("example.com", 1966, "/").unwrap();
+        assert_eq!(url.port(), Some(1966));
+    }
+
+    #[test]
+    fn resolve_reference_classifies_same_host_cross_host_and_unsupported_scheme() {
+        let current = Url::parse("gemini://example.com/dir/page.gmi").unwrap();
+
+        assert!(matches!(
+            resolve_reference(&current, "other.gmi"),
+            Reference::SameHost(url) if url.as_str() == "gemini://example.com/dir/other.gmi"
+        ));
+        assert!(matches!(
+            resolve_reference(&current, "gemini://other.com/"),
+            Reference::CrossHost(url) if url.host_str() == Some("other.com")
+        ));
+        assert!(matches!(
+            resolve_reference(&current, "https://example.com/"),
+            Reference::UnsupportedScheme(_)
+        ));
+    }
+
+    #[test]
+    fn resolve_reference_reports_the_raw_target_when_unparseable() {
+        let current = Url::parse("gemini://example.com/").unwrap();
+        let target = "gemini://ex ample.com/";
+
+        match resolve_reference(&current, target) {
+            Reference::Unparseable(raw) => assert_eq!(raw, target),
+            _ => panic!("expected Unparseable"),
+        }
+    }
+
+    #[test]
+    fn redirect_loop_detection_catches_a_url_redirecting_to_itself() {
+        // `fetch_with_redirects` tracks visited URLs in a `HashSet<Url>`;
+        // a redirect meta that resolves back to an already-visited URL must
+        // collide on re-insertion for the loop guard to trip.
+        let mut visited = HashSet::new();
+        let start = Url::parse("gemini://example.com/loop").unwrap();
+        let redirected_to = resolve_reference(&start, "/loop");
+        let Reference::SameHost(target) = redirected_to else {
+            panic!("expected a same-host reference");
+        };
+        assert!(visited.insert(start));
+        assert!(!visited.insert(target), "redirecting back to a visited URL must be detected");
+    }
+
+    #[test]
+    fn default_filename_appends_inferred_extension_when_missing() {
+        let url = Url::parse("gemini://example.com/photo").unwrap();
+        assert_eq!(default_filename(&url, "image/jpeg"), PathBuf::from("photo.jfif"));
+    }
+
+    #[test]
+    fn default_filename_does_not_double_up_a_valid_non_first_extension() {
+        // Regression test for the chunk0-6 bug: `image/jpeg`'s first
+        // candidate extension is "jfif", not "jpg", so a stem already
+        // ending in ".jpg" must not get a second extension appended.
+        let url = Url::parse("gemini://example.com/photo.jpg").unwrap();
+        assert_eq!(default_filename(&url, "image/jpeg"), PathBuf::from("photo.jpg"));
+    }
+
+    #[test]
+    fn default_filename_falls_back_to_download_for_an_empty_path() {
+        let url = Url::parse("gemini://example.com/").unwrap();
+        assert_eq!(default_filename(&url, "image/jpeg"), PathBuf::from("download.jfif"));
+    }
+
+    #[test]
+    fn default_filename_leaves_stem_alone_for_an_unrecognized_mime() {
+        let url = Url::parse("gemini://example.com/blob").unwrap();
+        assert_eq!(default_filename(&url, "application/does-not-exist"), PathBuf::from("blob"));
+    }
+
+    #[test]
+    fn save_to_file_writes_the_body_to_the_given_output_path() {
+        let path = std::env::temp_dir().join(format!("futago-test-{}.bin", std::process::id()));
+        let url = Url::parse("gemini://example.com/ignored").unwrap();
+        save_to_file(b"hello", "application/octet-stream", &url, Some(path.to_str().unwrap()));
+        let contents = fs::read(&path).expect("save_to_file should have written the file");
+        fs::remove_file(&path).ok();
+        assert_eq!(contents, b"hello");
+    }
+}
@@ -0,0 +1,356 @@
+//! Trust-on-first-use certificate verification.
+//!
+//! Gemini servers are expected to use self-signed certificates, so there is
+//! no CA chain to validate against. Instead we pin the leaf certificate's
+//! fingerprint the first time we see it, the same way `ssh` pins host keys,
+//! and refuse to proceed silently if it ever changes.
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme, StreamOwned};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a newly-trusted fingerprint is pinned. Until then a different
+/// certificate for the host is rejected as a mismatch; after it, a different
+/// certificate prompts the user again. The matching certificate stays
+/// trusted either way.
+const PIN_VALIDITY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+pub type GeminiStream = StreamOwned<ClientConnection, TcpStream>;
+
+#[derive(Debug, PartialEq, Eq)]
+struct KnownHostEntry {
+    fingerprint: String,
+    expiry: u64,
+}
+
+fn known_hosts_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("futago")
+        .join("known_hosts")
+}
+
+fn load_known_hosts(path: &PathBuf) -> HashMap<String, KnownHostEntry> {
+    let mut hosts = HashMap::new();
+    let Ok(contents) = fs::read_to_string(path) else {
+        return hosts;
+    };
+    for line in contents.lines() {
+        let mut parts = line.split_whitespace();
+        let (Some(host_port), Some(fingerprint), Some(expiry)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let Ok(expiry) = expiry.parse::<u64>() else {
+            continue;
+        };
+        hosts.insert(
+            host_port.to_owned(),
+            KnownHostEntry {
+                fingerprint: fingerprint.to_owned(),
+                expiry,
+            },
+        );
+    }
+    hosts
+}
+
+fn append_known_host(path: &PathBuf, host_port: &str, fingerprint: &str, expiry: u64) {
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .expect("failed to open known_hosts for writing");
+    writeln!(file, "{host_port} {fingerprint} {expiry}").expect("failed to write known_hosts");
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// What a known-hosts lookup says about a freshly-seen certificate.
+#[derive(Debug, PartialEq, Eq)]
+enum TrustDecision {
+    /// Fingerprint matches the pinned entry.
+    Trusted,
+    /// A pinned entry exists, hasn't expired, and disagrees with what the
+    /// server just presented.
+    Mismatched { expected: String },
+    /// No usable pin on record — either we've never seen this host, or the
+    /// pin we have has expired and the server now presents a different
+    /// certificate. Either way, the user needs to be asked.
+    Unknown,
+}
+
+fn decide_trust(
+    known: &HashMap<String, KnownHostEntry>,
+    host_port: &str,
+    fingerprint: &str,
+    now: u64,
+) -> TrustDecision {
+    match known.get(host_port) {
+        Some(entry) if entry.fingerprint == fingerprint => TrustDecision::Trusted,
+        Some(entry) if now < entry.expiry => TrustDecision::Mismatched {
+            expected: entry.fingerprint.clone(),
+        },
+        _ => TrustDecision::Unknown,
+    }
+}
+
+#[derive(Debug)]
+struct TofuVerifier {
+    host_port: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for TofuVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = Sha256::digest(end_entity.as_ref())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        let path = known_hosts_path();
+        let known = load_known_hosts(&path);
+        let now = now_unix();
+
+        match decide_trust(&known, &self.host_port, &fingerprint, now) {
+            TrustDecision::Trusted => Ok(ServerCertVerified::assertion()),
+            TrustDecision::Mismatched { expected } => Err(rustls::Error::General(format!(
+                "certificate for {} does not match the one on record (expected {}, got {}) \
+                 — someone may be intercepting your connection",
+                self.host_port, expected, fingerprint
+            ))),
+            TrustDecision::Unknown => {
+                println!(
+                    "The authenticity of host '{}' can't be established.",
+                    self.host_port
+                );
+                println!("SHA-256 fingerprint: {fingerprint}");
+                print!("Trust this certificate and remember it? [y/N] ");
+                io::stdout().flush().ok();
+                let mut answer = String::new();
+                io::stdin()
+                    .read_line(&mut answer)
+                    .map_err(|err| rustls::Error::General(err.to_string()))?;
+                if !answer.trim().eq_ignore_ascii_case("y") {
+                    return Err(rustls::Error::General(
+                        "certificate rejected by user".to_owned(),
+                    ));
+                }
+                append_known_host(
+                    &path,
+                    &self.host_port,
+                    &fingerprint,
+                    now + PIN_VALIDITY.as_secs(),
+                );
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Connects to `host:port` over TLS, pinning the server's certificate via
+/// trust-on-first-use instead of validating it against a CA.
+///
+/// Drives the handshake to completion before returning, rather than leaving
+/// it to happen lazily on the caller's first read/write: that's the only
+/// way a rejected or mismatched certificate — which `TofuVerifier` reports
+/// as a handshake error — comes back as an `Err` here instead of surfacing
+/// later as an unrelated I/O failure deep in request-sending code.
+pub fn create_stream(host: &str, port: u16) -> Result<GeminiStream, String> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let verifier = Arc::new(TofuVerifier {
+        host_port: format!("{host}:{port}"),
+        provider,
+    });
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    let server_name = ServerName::try_from(host.to_owned()).expect("invalid server name");
+    let mut conn = ClientConnection::new(Arc::new(config), server_name).expect("tls setup failed");
+    let mut stream = TcpStream::connect((host, port)).expect("tcp connect failed");
+    conn.complete_io(&mut stream)
+        .map_err(|err| format!("TLS handshake with {host}:{port} failed: {err}"))?;
+    Ok(StreamOwned::new(conn, stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("futago-test-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn load_known_hosts_parses_well_formed_lines() {
+        let path = scratch_path("load-well-formed");
+        fs::write(&path, "example.com:1965 abcd1234 1999999999\n").unwrap();
+        let hosts = load_known_hosts(&path);
+        fs::remove_file(&path).ok();
+        assert_eq!(
+            hosts.get("example.com:1965"),
+            Some(&KnownHostEntry {
+                fingerprint: "abcd1234".to_owned(),
+                expiry: 1999999999,
+            })
+        );
+    }
+
+    #[test]
+    fn load_known_hosts_skips_malformed_lines() {
+        let path = scratch_path("load-malformed");
+        fs::write(
+            &path,
+            "missing-fields-only-host\n\
+             example.com:1965 abcd1234 not-a-number\n\
+             good.example:1965 ffff0000 1999999999\n",
+        )
+        .unwrap();
+        let hosts = load_known_hosts(&path);
+        fs::remove_file(&path).ok();
+        assert_eq!(hosts.len(), 1);
+        assert!(hosts.contains_key("good.example:1965"));
+    }
+
+    #[test]
+    fn load_known_hosts_returns_empty_for_a_missing_file() {
+        let path = scratch_path("load-missing");
+        assert!(load_known_hosts(&path).is_empty());
+    }
+
+    #[test]
+    fn append_known_host_roundtrips_through_load_known_hosts() {
+        let path = scratch_path("append-roundtrip");
+        append_known_host(&path, "example.com:1965", "abcd1234", 1999999999);
+        let hosts = load_known_hosts(&path);
+        fs::remove_file(&path).ok();
+        assert_eq!(
+            hosts.get("example.com:1965"),
+            Some(&KnownHostEntry {
+                fingerprint: "abcd1234".to_owned(),
+                expiry: 1999999999,
+            })
+        );
+    }
+
+    #[test]
+    fn decide_trust_is_trusted_when_fingerprint_matches() {
+        let mut known = HashMap::new();
+        known.insert(
+            "example.com:1965".to_owned(),
+            KnownHostEntry {
+                fingerprint: "abcd1234".to_owned(),
+                expiry: 1999999999,
+            },
+        );
+        assert_eq!(
+            decide_trust(&known, "example.com:1965", "abcd1234", 1_000_000_000),
+            TrustDecision::Trusted
+        );
+    }
+
+    #[test]
+    fn decide_trust_is_mismatched_when_pin_is_still_valid_and_differs() {
+        let mut known = HashMap::new();
+        known.insert(
+            "example.com:1965".to_owned(),
+            KnownHostEntry {
+                fingerprint: "abcd1234".to_owned(),
+                expiry: 1999999999,
+            },
+        );
+        assert_eq!(
+            decide_trust(&known, "example.com:1965", "deadbeef", 1_000_000_000),
+            TrustDecision::Mismatched {
+                expected: "abcd1234".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn decide_trust_is_unknown_when_there_is_no_entry() {
+        let known = HashMap::new();
+        assert_eq!(
+            decide_trust(&known, "example.com:1965", "abcd1234", 1_000_000_000),
+            TrustDecision::Unknown
+        );
+    }
+
+    #[test]
+    fn decide_trust_is_unknown_when_the_mismatched_pin_has_expired() {
+        let mut known = HashMap::new();
+        known.insert(
+            "example.com:1965".to_owned(),
+            KnownHostEntry {
+                fingerprint: "abcd1234".to_owned(),
+                expiry: 1_000_000_000,
+            },
+        );
+        // Expired, so even though the fingerprint differs we should re-prompt
+        // rather than report a mismatch.
+        assert_eq!(
+            decide_trust(&known, "example.com:1965", "deadbeef", 2_000_000_000),
+            TrustDecision::Unknown
+        );
+    }
+}